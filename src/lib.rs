@@ -1,132 +1,488 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 
-use serde::de::{self, MapAccess, Visitor};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize};
 
+/// Chain specs sometimes encode integers as quantity strings (`"0x1388"`, `"5000"`)
+/// instead of JSON numbers. This module provides a `deserialize_with` helper, modeled
+/// on serde_with's `DisplayFromStr`, that accepts either form and normalizes to `u64`.
+mod quantity {
+    use std::convert::TryFrom;
+    use std::fmt;
+
+    use serde::de::{self, Visitor};
+    use serde::{Deserialize, Deserializer};
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct QuantityVisitor;
+
+        impl<'de> Visitor<'de> for QuantityVisitor {
+            type Value = u64;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a u64 integer or a hex/decimal quantity string")
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<u64, E>
+            where
+                E: de::Error,
+            {
+                Ok(value)
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<u64, E>
+            where
+                E: de::Error,
+            {
+                u64::try_from(value)
+                    .map_err(|_| de::Error::custom(format!("negative quantity: {}", value)))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<u64, E>
+            where
+                E: de::Error,
+            {
+                match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+                    Some(rest) => u64::from_str_radix(rest, 16).map_err(de::Error::custom),
+                    None => value.parse::<u64>().map_err(de::Error::custom),
+                }
+            }
+        }
+
+        // Non-self-describing formats (e.g. bincode) can't drive `deserialize_any` at
+        // all, and don't need the string fallback anyway since they have no notion of
+        // a quantity string — go straight to `deserialize_u64` for those.
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(QuantityVisitor)
+        } else {
+            deserializer.deserialize_u64(QuantityVisitor)
+        }
+    }
+
+    /// Wrapper so the manual `Builtin` visitor can pull a quantity out of a
+    /// `MapAccess` via `next_value::<Quantity>()` the same way derive-based
+    /// structs do via `#[serde(deserialize_with = "quantity::deserialize")]`.
+    pub(crate) struct Quantity(pub(crate) u64);
+
+    impl<'de> Deserialize<'de> for Quantity {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserialize(deserializer).map(Quantity)
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Pricing {
+    #[serde(deserialize_with = "quantity::deserialize")]
     price: u64,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct PricingAt {
+    #[serde(deserialize_with = "quantity::deserialize")]
     price: u64,
+    #[serde(deserialize_with = "quantity::deserialize")]
     at: u64,
 }
 
-#[derive(Clone, Debug)]
-struct Builtin {
-    name: String,
-    pricing: Vec<PricingAt>,
-    at: u64,
+/// The `pricing` field of a `Builtin` can show up in chain specs either as a single
+/// flat object (`{"price": 1000}`) or as an array of `PricingAt` entries. This buffers
+/// both shapes so `BuiltinVisitor` can normalize them into `Vec<PricingAt>` once the
+/// enclosing `Builtin`'s `at` is known.
+enum PricingField {
+    Single(Pricing),
+    Many(Vec<PricingAt>),
 }
 
-impl<'de> Deserialize<'de> for Builtin {
+impl<'de> Deserialize<'de> for PricingField {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        enum Field {
-            Name,
-            Pricing,
-            At,
-        };
+        struct PricingFieldVisitor;
 
-        impl<'de> Deserialize<'de> for Field {
-            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-            where
-                D: Deserializer<'de>,
-            {
-                struct FieldVisitor;
+        impl<'de> Visitor<'de> for PricingFieldVisitor {
+            type Value = PricingField;
 
-                impl<'de> Visitor<'de> for FieldVisitor {
-                    type Value = Field;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a pricing object or an array of pricing entries")
+            }
 
-                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                        formatter.write_str("`name`, `pricing` or `at`")
-                    }
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let pricing = Pricing::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(PricingField::Single(pricing))
+            }
 
-                    fn visit_str<E>(self, value: &str) -> Result<Field, E>
-                    where
-                        E: de::Error,
-                    {
-                        match value {
-                            "name" => Ok(Field::Name),
-                            "pricing" => Ok(Field::Pricing),
-                            "at" => Ok(Field::At),
-                            _ => Err(de::Error::unknown_field(value, FIELDS)),
-                        }
-                    }
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut entries = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(entry) = seq.next_element()? {
+                    entries.push(entry);
                 }
-                deserializer.deserialize_identifier(FieldVisitor)
+                Ok(PricingField::Many(entries))
             }
         }
 
-        struct BuiltinVisitor;
+        // Binary formats have no single-object-vs-array ambiguity to resolve (that's
+        // a JSON chain-spec quirk), and can't drive `deserialize_any` regardless, so
+        // just deserialize the `Vec<PricingAt>` shape directly.
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(PricingFieldVisitor)
+        } else {
+            Vec::<PricingAt>::deserialize(deserializer).map(PricingField::Many)
+        }
+    }
+}
+
+/// Distinguishes the three ways a chain spec can express `at`: a concrete value,
+/// an explicit `null` (activate at genesis), or the key left out entirely (inherit
+/// whatever default the caller applies). `Builtin::at` collapses `Null`/`Missing`
+/// (when allowed) to `0`; this is for callers that need to tell the cases apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtField {
+    Present(u64),
+    Null,
+    Missing,
+}
+
+#[derive(Clone, Debug)]
+pub struct Builtin {
+    pub name: String,
+    pub pricing: Vec<PricingAt>,
+    pub at: u64,
+    pub at_field: AtField,
+}
+
+const BUILTIN_FIELDS: &[&str] = &["name", "pricing", "at"];
+
+enum Field {
+    Name,
+    Pricing,
+    At,
+}
+
+impl<'de> Deserialize<'de> for Field {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct FieldVisitor;
 
-        impl<'de> Visitor<'de> for BuiltinVisitor {
-            type Value = Builtin;
+        impl<'de> Visitor<'de> for FieldVisitor {
+            type Value = Field;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("struct Builtin")
+                formatter.write_str("`name`, `pricing` or `at`")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Field, E>
+            where
+                E: de::Error,
+            {
+                match value {
+                    "name" => Ok(Field::Name),
+                    "pricing" => Ok(Field::Pricing),
+                    "at" => Ok(Field::At),
+                    _ => Err(de::Error::unknown_field(value, BUILTIN_FIELDS)),
+                }
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Field, E>
+            where
+                E: de::Error,
+            {
+                match value {
+                    0 => Ok(Field::Name),
+                    1 => Ok(Field::Pricing),
+                    2 => Ok(Field::At),
+                    _ => Err(de::Error::unknown_field(&value.to_string(), BUILTIN_FIELDS)),
+                }
+            }
+
+            fn visit_bytes<E>(self, value: &[u8]) -> Result<Field, E>
+            where
+                E: de::Error,
+            {
+                match std::str::from_utf8(value) {
+                    Ok(value) => self.visit_str(value),
+                    Err(_) => Err(de::Error::invalid_value(de::Unexpected::Bytes(value), &self)),
+                }
             }
 
-            fn visit_map<V>(self, mut map: V) -> Result<Builtin, V::Error>
+            fn visit_borrowed_bytes<E>(self, value: &'de [u8]) -> Result<Field, E>
             where
-                V: MapAccess<'de>,
+                E: de::Error,
             {
-                let mut name = None;
-                let mut pricing = None;
-                let mut at = None;
-
-                while let Some(key) = map.next_key()? {
-                    match key {
-                        Field::Name => {
-                            if name.is_some() {
-                                return Err(de::Error::duplicate_field("name"));
-                            }
-                            name = Some(map.next_value()?);
-                        }
-                        Field::Pricing => {
-                            if pricing.is_some() {
-                                return Err(de::Error::duplicate_field("pricing"));
-                            }
-                            pricing = Some(map.next_value()?);
-                        }
-                        Field::At => {
-                            if at.is_some() {
-                                return Err(de::Error::duplicate_field("at"));
-                            }
-                            at = Some(map.next_value()?);
-                        }
+                self.visit_bytes(value)
+            }
+        }
+        deserializer.deserialize_identifier(FieldVisitor)
+    }
+}
+
+/// Resolves the three buffered `Builtin` parts (name, polymorphic pricing, and the
+/// tri-state `at`) into a `Builtin`, shared by both `visit_map` (self-describing
+/// formats) and `visit_seq` (positional formats like bincode). `allow_missing_at`
+/// controls whether an omitted/absent `at` is an error or is treated as genesis.
+fn resolve_builtin<E>(
+    name: String,
+    pricing: PricingField,
+    at: Option<Option<u64>>,
+    allow_missing_at: bool,
+) -> Result<Builtin, E>
+where
+    E: de::Error,
+{
+    let at_field = match at {
+        Some(Some(value)) => AtField::Present(value),
+        Some(None) => AtField::Null,
+        None => AtField::Missing,
+    };
+
+    let at = match at_field {
+        AtField::Present(value) => value,
+        AtField::Null => 0,
+        AtField::Missing => {
+            if allow_missing_at {
+                0
+            } else {
+                return Err(de::Error::missing_field("at"));
+            }
+        }
+    };
+
+    let pricing = match pricing {
+        PricingField::Many(pricing) => pricing,
+        PricingField::Single(Pricing { price }) => vec![PricingAt { price, at }],
+    };
+
+    Ok(Builtin {
+        name,
+        pricing,
+        at,
+        at_field,
+    })
+}
+
+/// Shared `Visitor` body for `Builtin`. `allow_missing_at` controls whether an
+/// omitted `at` key is an error (the default, via `Deserialize for Builtin`) or is
+/// treated like an explicit `null` (via `BuiltinSeed`). Implements both `visit_map`
+/// (JSON and other self-describing formats) and `visit_seq` (positional formats
+/// like bincode, which encode a struct as a plain tuple of its fields).
+struct BuiltinVisitor {
+    allow_missing_at: bool,
+}
+
+impl<'de> Visitor<'de> for BuiltinVisitor {
+    type Value = Builtin;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("struct Builtin")
+    }
+
+    fn visit_map<V>(self, mut map: V) -> Result<Builtin, V::Error>
+    where
+        V: MapAccess<'de>,
+    {
+        let mut name = None;
+        let mut pricing = None;
+        let mut at: Option<Option<u64>> = None;
+
+        while let Some(key) = map.next_key()? {
+            match key {
+                Field::Name => {
+                    if name.is_some() {
+                        return Err(de::Error::duplicate_field("name"));
+                    }
+                    name = Some(map.next_value()?);
+                }
+                Field::Pricing => {
+                    if pricing.is_some() {
+                        return Err(de::Error::duplicate_field("pricing"));
+                    }
+                    pricing = Some(map.next_value()?);
+                }
+                Field::At => {
+                    if at.is_some() {
+                        return Err(de::Error::duplicate_field("at"));
                     }
+                    at = Some(map.next_value::<Option<quantity::Quantity>>()?.map(|q| q.0));
                 }
+            }
+        }
+
+        let name = name.ok_or_else(|| de::Error::missing_field("name"))?;
+        let pricing = pricing.ok_or_else(|| de::Error::missing_field("pricing"))?;
+
+        resolve_builtin(name, pricing, at, self.allow_missing_at)
+    }
+
+    fn visit_seq<V>(self, mut seq: V) -> Result<Builtin, V::Error>
+    where
+        V: SeqAccess<'de>,
+    {
+        let name: String = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let pricing: PricingField = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let at: Option<quantity::Quantity> = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+
+        resolve_builtin(name, pricing, Some(at.map(|q| q.0)), self.allow_missing_at)
+    }
+}
+
+impl<'de> Deserialize<'de> for Builtin {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_struct(
+            "Builtin",
+            BUILTIN_FIELDS,
+            BuiltinVisitor {
+                allow_missing_at: false,
+            },
+        )
+    }
+}
+
+/// `DeserializeSeed` backing [`deserialize_builtin_lenient`]: parses a `Builtin` where
+/// an omitted `at` key is treated like an explicit `null` (genesis) instead of an
+/// error. Use `Builtin::deserialize` directly when the stricter, current behavior
+/// (error on missing `at`) is wanted.
+struct BuiltinSeed {
+    allow_missing_at: bool,
+}
+
+impl<'de> de::DeserializeSeed<'de> for BuiltinSeed {
+    type Value = Builtin;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_struct(
+            "Builtin",
+            BUILTIN_FIELDS,
+            BuiltinVisitor {
+                allow_missing_at: self.allow_missing_at,
+            },
+        )
+    }
+}
 
-                let name = name.ok_or_else(|| de::Error::missing_field("name"))?;
-                let pricing = pricing.ok_or_else(|| de::Error::missing_field("pricing"))?;
-                let at = at.ok_or_else(|| de::Error::missing_field("at"))?;
+/// Opt-in entry point for parsing a `Builtin` where an omitted `at` key is treated
+/// like an explicit `null` (genesis) instead of an error. `Builtin::deserialize`
+/// keeps the stricter, current behavior (error on missing `at`) for existing callers.
+pub fn deserialize_builtin_lenient<'de, D>(deserializer: D) -> Result<Builtin, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use de::DeserializeSeed;
+    BuiltinSeed {
+        allow_missing_at: true,
+    }
+    .deserialize(deserializer)
+}
 
-                // TODO(niklasad1): how to check if pricing is of type `Amount` or `AmountAt`?
-                // Then
-                // ```rust
-                //  let amount_at: AmountAt = amount.into();
-                //  vec![amount_at]
-                //```
-                //
-                // It could be possible to `serde_json::{to_value, from_value}` deserialize here to check
+/// Like `Builtin`, but with its pricing table behind an `Rc<[PricingAt]>`. Produced by
+/// [`deserialize_builtins_shared`], which interns structurally-equal pricing tables so
+/// they share one allocation instead of each builtin owning its own `Vec`.
+#[derive(Clone, Debug)]
+pub struct BuiltinShared {
+    pub name: String,
+    pub pricing: Rc<[PricingAt]>,
+    pub at: u64,
+}
+
+/// `DeserializeSeed` that parses a `Builtin` and canonicalizes its pricing table
+/// through `interner`: a structurally-equal `Vec<PricingAt>` already seen reuses the
+/// existing `Rc`, so two builtins whose pricing compares `Eq` end up pointer-equal.
+struct BuiltinSharedSeed<'a> {
+    interner: &'a mut HashMap<Vec<PricingAt>, Rc<[PricingAt]>>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for BuiltinSharedSeed<'a> {
+    type Value = BuiltinShared;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let builtin = Builtin::deserialize(deserializer)?;
 
-                Ok(Builtin { name, pricing, at })
+        let pricing = match self.interner.get(&builtin.pricing) {
+            Some(shared) => shared.clone(),
+            None => {
+                let shared: Rc<[PricingAt]> = Rc::from(builtin.pricing.clone().into_boxed_slice());
+                self.interner.insert(builtin.pricing, shared.clone());
+                shared
             }
+        };
+
+        Ok(BuiltinShared {
+            name: builtin.name,
+            pricing,
+            at: builtin.at,
+        })
+    }
+}
+
+/// Deserializes a whole list of `Builtin`s while interning repeated pricing tables
+/// behind `Rc<[PricingAt]>`, so chain specs with dozens of builtins sharing identical
+/// pricing schedules don't pay for a separate allocation per builtin.
+pub fn deserialize_builtins_shared<'de, D>(deserializer: D) -> Result<Vec<BuiltinShared>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct BuiltinsSharedVisitor;
+
+    impl<'de> Visitor<'de> for BuiltinsSharedVisitor {
+        type Value = Vec<BuiltinShared>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a sequence of builtins")
         }
 
-        const FIELDS: &[&str] = &["name", "pricing", "at"];
-        deserializer.deserialize_struct("Builtin", FIELDS, BuiltinVisitor)
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut interner = HashMap::new();
+            let mut builtins = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(builtin) =
+                seq.next_element_seed(BuiltinSharedSeed { interner: &mut interner })?
+            {
+                builtins.push(builtin);
+            }
+            Ok(builtins)
+        }
     }
+
+    deserializer.deserialize_seq(BuiltinsSharedVisitor)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Builtin, PricingAt};
+    use super::{deserialize_builtin_lenient, deserialize_builtins_shared, AtField, Builtin, PricingAt};
+    use std::rc::Rc;
 
     #[test]
     fn deserialize_empty_vec() {
@@ -161,8 +517,27 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
-    // don't work
+    fn deserialize_vec_with_quantity_strings() {
+        let raw = r#"{
+            "name": "bar",
+            "pricing": [ {"price": "0x64", "at": "0"}, {"price": "5000", "at": 11} ],
+            "at": "0x1"
+        }"#;
+        let builtin: Builtin = serde_json::from_str(raw).unwrap();
+        assert_eq!(
+            builtin.pricing,
+            vec![
+                PricingAt { price: 100, at: 0 },
+                PricingAt {
+                    price: 5000,
+                    at: 11
+                }
+            ]
+        );
+        assert_eq!(builtin.at, 1);
+    }
+
+    #[test]
     fn deserialize_object() {
         let raw = r#"{
             "name": "foo",
@@ -175,10 +550,84 @@ mod tests {
         assert_eq!(
             builtin.pricing,
             vec![PricingAt {
-                price: 100,
+                price: 1000,
                 at: 999
             }]
         );
         assert_eq!(builtin.at, 999);
     }
+
+    #[test]
+    fn deserialize_object_without_at_fails() {
+        let raw = r#"{
+            "name": "foo",
+            "pricing": { "price": 1000 }
+        }"#;
+
+        assert!(serde_json::from_str::<Builtin>(raw).is_err());
+    }
+
+    #[test]
+    fn at_explicit_null_is_genesis_not_an_error() {
+        let raw = r#"{
+            "name": "foo",
+            "pricing": [],
+            "at": null
+        }"#;
+        let builtin: Builtin = serde_json::from_str(raw).unwrap();
+        assert_eq!(builtin.at, 0);
+        assert_eq!(builtin.at_field, AtField::Null);
+    }
+
+    #[test]
+    fn at_missing_is_an_error_by_default() {
+        let raw = r#"{
+            "name": "foo",
+            "pricing": []
+        }"#;
+        assert!(serde_json::from_str::<Builtin>(raw).is_err());
+    }
+
+    #[test]
+    fn deserialize_builtin_lenient_allows_missing_at() {
+        let raw = r#"{
+            "name": "foo",
+            "pricing": []
+        }"#;
+        let mut de = serde_json::Deserializer::from_str(raw);
+        let builtin = deserialize_builtin_lenient(&mut de).unwrap();
+        assert_eq!(builtin.at, 0);
+        assert_eq!(builtin.at_field, AtField::Missing);
+    }
+
+    #[test]
+    fn roundtrips_through_bincode() {
+        // bincode is non-self-describing and encodes a struct positionally, so this
+        // exercises `BuiltinVisitor::visit_seq` plus the binary-format fallbacks in
+        // `quantity::deserialize` and `PricingField::deserialize`.
+        let pricing = vec![PricingAt { price: 100, at: 0 }, PricingAt { price: 0, at: 11 }];
+        let bytes = bincode::serialize(&("bar".to_string(), &pricing, Some(5u64))).unwrap();
+
+        let builtin: Builtin = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(builtin.name, "bar".to_string());
+        assert_eq!(builtin.pricing, pricing);
+        assert_eq!(builtin.at, 5);
+        assert_eq!(builtin.at_field, AtField::Present(5));
+    }
+
+    #[test]
+    fn deserialize_builtins_shared_interns_equal_pricing_tables() {
+        let raw = r#"[
+            { "name": "foo", "pricing": [ {"price": 100, "at": 0} ], "at": 0 },
+            { "name": "bar", "pricing": [ {"price": 100, "at": 0} ], "at": 1 },
+            { "name": "baz", "pricing": [ {"price": 200, "at": 0} ], "at": 2 }
+        ]"#;
+        let mut de = serde_json::Deserializer::from_str(raw);
+        let builtins = deserialize_builtins_shared(&mut de).unwrap();
+
+        assert_eq!(builtins.len(), 3);
+        assert!(Rc::ptr_eq(&builtins[0].pricing, &builtins[1].pricing));
+        assert!(!Rc::ptr_eq(&builtins[0].pricing, &builtins[2].pricing));
+        assert_eq!(&*builtins[2].pricing, &[PricingAt { price: 200, at: 0 }]);
+    }
 }